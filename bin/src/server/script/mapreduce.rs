@@ -1,161 +1,213 @@
-use rlua::{Table, Function};
+use rlua::{Lua, Table, Function};
 use serde_json::value::Value as JsonValue;
 use uuid::Uuid;
 use indradb::Vertex;
 use statics;
-use crossbeam_channel::{Receiver, Sender, bounded};
-use std::time::Duration;
-use std::thread::{spawn, JoinHandle};
+use crossbeam_channel::{Sender, bounded};
+use std::time::{Duration, Instant};
+use std::thread::{spawn, sleep, JoinHandle};
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::cell::RefCell;
+use rayon::{ThreadPool, ThreadPoolBuilder};
 use super::errors;
 use super::context;
 use super::converters;
 
 const CHANNEL_CAPACITY: usize = 1000;
-const CHANNEL_RECV_TIMEOUT_SECONDS: u64 = 1;
 const REPORT_SECONDS: u64 = 30;
+// How many recent map task durations the tranquilizer keeps around to
+// compute its moving average over.
+const TRANQUILIZER_WINDOW: usize = 20;
 
-macro_rules! try_or_send {
-    ($expr:expr, $error_mapper:expr, $error_sender:expr) => {
-        match $expr {
-            Ok(value) => value,
-            Err(err) => {
-                $error_sender.send($error_mapper(err)).expect("Expected error channel to be open");
-                return;
-            }
+/// A snapshot of a mapreduce job's progress, emitted on every reporter tick.
+/// This replaces ad-hoc logging: embedders can forward these to a dashboard,
+/// a metrics system, or just log them, by reading off the channel passed to
+/// `MapReduceWorkerPool::start`.
+#[derive(Clone, Debug)]
+pub struct ProgressReport {
+    pub processed: usize,
+    pub pending: usize,
+    pub retries: usize,
+    pub elapsed: Duration,
+    pub throughput_per_sec: f64
+}
+
+#[derive(Clone)]
+enum MapReduceWorkerTask {
+    Map(Vertex, u32),
+    Reduce((String, converters::JsonValue, converters::JsonValue), u32)
+}
+
+impl MapReduceWorkerTask {
+    fn attempt(&self) -> u32 {
+        match *self {
+            MapReduceWorkerTask::Map(_, attempt) => attempt,
+            MapReduceWorkerTask::Reduce(_, attempt) => attempt
         }
     }
-}
 
-enum WorkerTask {
-    Map(Vertex),
-    Reduce((converters::JsonValue, converters::JsonValue))
+    fn retry(self) -> Self {
+        match self {
+            MapReduceWorkerTask::Map(vertex, attempt) => MapReduceWorkerTask::Map(vertex, attempt + 1),
+            MapReduceWorkerTask::Reduce(payload, attempt) => MapReduceWorkerTask::Reduce(payload, attempt + 1)
+        }
+    }
 }
 
-struct Worker {
-    thread: JoinHandle<()>,
-    shutdown_sender: Sender<()>
+// What a worker hands back to the router: either the (key, value) pairs
+// emitted by `map`, or the single value produced by reducing two values
+// that shared a key.
+enum MapReduceWorkerOutput {
+    Mapped(Vec<(String, converters::JsonValue)>),
+    Reduced((String, converters::JsonValue))
 }
 
-impl Worker {
-    fn start(account_id: Uuid, contents: String, path: String, arg: JsonValue, in_receiver: Receiver<WorkerTask>, out_sender: Sender<converters::JsonValue>, error_sender: Sender<errors::MapReduceError>) -> Self {
-        let (shutdown_sender, shutdown_receiver) = bounded::<()>(1);
+thread_local! {
+    // Lazily built the first time this rayon worker thread runs a task for a
+    // given job, then reused for every task it picks up afterwards, so the
+    // script doesn't get recompiled per-task.
+    static LUA_CONTEXT: RefCell<Option<(Lua, Function, Function)>> = RefCell::new(None);
+}
 
-        let thread = spawn(move || {
-            let mut should_shutdown = false;
+fn run_task(account_id: Uuid, contents: &str, path: &str, arg: JsonValue, task: MapReduceWorkerTask) -> Result<MapReduceWorkerOutput, errors::MapReduceError> {
+    LUA_CONTEXT.with(|cell| {
+        let mut cell = cell.borrow_mut();
 
-            let l = try_or_send!(
-                context::create(account_id, arg),
-                |err| errors::MapReduceError::WorkerSetup {
+        if cell.is_none() {
+            let l = context::create(account_id, arg)
+                .map_err(|err| errors::MapReduceError::WorkerSetup {
                     description: "Error occurred trying to to create a lua context".to_string(),
                     cause: err
-                },
-                error_sender
-            );
+                })?;
 
-            let table: Table = try_or_send!(
-                l.exec(&contents, Some(&path)),
-                |err| errors::MapReduceError::WorkerSetup {
+            let table: Table = l.exec(contents, Some(path))
+                .map_err(|err| errors::MapReduceError::WorkerSetup {
                     description: "Error occurred trying to get a table from the mapreduce script".to_string(),
                     cause: errors::ScriptError::Lua(err)
-                },
-                error_sender
-            );
+                })?;
 
-            let mapper: Function = try_or_send!(
-                table.get("map"),
-                |err| errors::MapReduceError::WorkerSetup {
+            let mapper: Function = table.get("map")
+                .map_err(|err| errors::MapReduceError::WorkerSetup {
                     description: "Error occurred trying to get the `map` function from the returned table".to_string(),
                     cause: errors::ScriptError::Lua(err)
-                },
-                error_sender
-            );
+                })?;
 
-            let reducer: Function = try_or_send!(
-                table.get("reduce"),
-                |err| errors::MapReduceError::WorkerSetup {
+            let reducer: Function = table.get("reduce")
+                .map_err(|err| errors::MapReduceError::WorkerSetup {
                     description: "Error occurred trying to get the `reduce` function from the returned table".to_string(),
                     cause: errors::ScriptError::Lua(err)
-                },
-                error_sender
-            );
+                })?;
 
-            loop {
-                select_loop! {
-                    recv(in_receiver, task) => {
-                        let value = match task {
-                            WorkerTask::Map(vertex) => {
-                                try_or_send!(
-                                    mapper.call(converters::Vertex::new(vertex)),
-                                    |err| errors::MapReduceError::MapCall(err),
-                                    error_sender
-                                )
-                            },
-                            WorkerTask::Reduce((first, second)) => {
-                                try_or_send!(
-                                    reducer.call((first, second)),
-                                    |err| errors::MapReduceError::ReduceCall(err),
-                                    error_sender
-                                )
-                            }
-                        };
-
-                        out_sender.send(value).expect("Expected worker output channel to be open");
-                    },
-                    recv(shutdown_receiver, _) => {
-                        should_shutdown = true;
-                    },
-                    timed_out(Duration::from_secs(CHANNEL_RECV_TIMEOUT_SECONDS)) => {}
-                }
+            *cell = Some((l, mapper, reducer));
+        }
 
-                if should_shutdown {
-                    return;
-                }
-            }
-        });
+        let &(_, ref mapper, ref reducer) = cell.as_ref().unwrap();
 
-        Self {
-            thread: thread,
-            shutdown_sender: shutdown_sender
+        match task {
+            MapReduceWorkerTask::Map(vertex, _) => {
+                mapper.call(converters::Vertex::new(vertex))
+                    .map_err(errors::MapReduceError::MapCall)
+                    .and_then(|emitted: Table| {
+                        emitted.pairs::<String, converters::JsonValue>()
+                            .collect::<Result<Vec<_>, _>>()
+                            .map_err(errors::MapReduceError::MapCall)
+                    })
+                    .map(MapReduceWorkerOutput::Mapped)
+            },
+            MapReduceWorkerTask::Reduce((key, first, second), _) => {
+                reducer.call((key.clone(), first, second))
+                    .map_err(errors::MapReduceError::ReduceCall)
+                    .map(|reduced| MapReduceWorkerOutput::Reduced((key, reduced)))
+            }
         }
-    }
+    })
+}
 
-    fn join(self) {
-        // This ignores the error. An error should only occur if the remote
-        // end of the channel disconnected, implying that the thread crashed
-        // anyways.
-        self.shutdown_sender.send(()).ok();
-        self.thread.join().expect("Expected worker thread to not panic")
-    }
+// One slot per configured rayon worker thread, indexed by
+// `rayon::current_thread_index()`. `Some(started_at)` means that worker is
+// currently in the middle of a task; this is what lets a bounded shutdown
+// report exactly which workers are still alive past the deadline.
+type WorkerLiveness = Vec<Mutex<Option<Instant>>>;
+
+fn new_worker_liveness(pool_size: usize) -> Arc<WorkerLiveness> {
+    Arc::new((0..pool_size).map(|_| Mutex::new(None)).collect())
 }
 
-pub struct WorkerPool {
+// Submits a task onto the rayon pool. The worker that picks it up reuses its
+// thread-local Lua context if it already ran a task for this job, and either
+// of the two channels below carries the outcome back to the router.
+//
+// The dispatch instant is stamped here, right as the task is handed to the
+// pool, and travels back with the result instead of being recovered later by
+// matching completions against a shared FIFO: rayon completes tasks out of
+// order, and a retried task would otherwise desync a dispatch-time queue
+// from the completions it pairs against.
+// Every dispatched task resolves exactly once, on exactly one of these two
+// channels: `out_sender` on success, `retry_sender` on any failure
+// (including non-retryable `WorkerSetup` failures, identified and funneled
+// straight to the fatal error channel by the router's retry branch). That
+// keeps `pending_tasks` bookkeeping single-sourced in the router instead of
+// risking an error that's counted twice, or not at all.
+fn dispatch(rayon_pool: &ThreadPool, account_id: Uuid, contents: Arc<String>, path: Arc<String>, arg: JsonValue, task: MapReduceWorkerTask, out_sender: Sender<(MapReduceWorkerOutput, Instant)>, retry_sender: Sender<(MapReduceWorkerTask, errors::MapReduceError)>, worker_liveness: Arc<WorkerLiveness>) {
+    let dispatched_at = Instant::now();
+
+    rayon_pool.spawn(move || {
+        let retry_task = task.clone();
+        let worker_index = rayon::current_thread_index().unwrap_or(0);
+
+        *worker_liveness[worker_index].lock().expect("Expected worker liveness mutex to not be poisoned") = Some(Instant::now());
+
+        let result = run_task(account_id, &contents, &path, arg, task);
+
+        *worker_liveness[worker_index].lock().expect("Expected worker liveness mutex to not be poisoned") = None;
+
+        match result {
+            Ok(value) => out_sender.send((value, dispatched_at)).expect("Expected worker output channel to be open"),
+            Err(err) => retry_sender.send((retry_task, err)).expect("Expected retry channel to be open")
+        }
+    });
+}
+
+pub struct MapReduceWorkerPool {
     reporter_thread: JoinHandle<()>,
     router_thread: JoinHandle<Result<JsonValue, errors::MapReduceError>>,
     in_sender: Sender<Vertex>,
-    shutdown_sender: Sender<()>
+    // `None` means "wait as long as it takes"; `Some(timeout)` bounds how
+    // long the router will wait for in-flight tasks to report back before
+    // giving up and reporting which worker slots are still stuck.
+    shutdown_sender: Sender<Option<Duration>>,
+    // The delay `add_vertex` should sleep for before handing off its vertex,
+    // kept up to date by the router's tranquilizer so producers throttle
+    // themselves in proportion to how busy the workers already are.
+    throttle_delay: Arc<Mutex<Duration>>
 }
 
-impl WorkerPool {
-    pub fn start(account_id: Uuid, contents: String, path: String, arg: JsonValue) -> Self {
+impl MapReduceWorkerPool {
+    // `pool_size` lets the caller size the rayon pool per-job instead of
+    // being stuck with a single process-wide worker count; pass
+    // `*statics::MAP_REDUCE_WORKER_POOL_SIZE as usize` to keep the old
+    // behavior.
+    pub fn start(account_id: Uuid, contents: String, path: String, arg: JsonValue, pool_size: usize, progress_sender: Option<Sender<ProgressReport>>) -> Self {
         let (mapreduce_in_sender, mapreduce_in_receiver) = bounded::<Vertex>(CHANNEL_CAPACITY);
-        let (worker_in_sender, worker_in_receiver) = bounded::<WorkerTask>(CHANNEL_CAPACITY);
-        let (worker_out_sender, worker_out_receiver) = bounded::<converters::JsonValue>(CHANNEL_CAPACITY);
-        let (error_sender, error_receiver) = bounded::<errors::MapReduceError>(*statics::MAP_REDUCE_WORKER_POOL_SIZE as usize);
+        let (worker_out_sender, worker_out_receiver) = bounded::<(MapReduceWorkerOutput, Instant)>(CHANNEL_CAPACITY);
+        let (retry_sender, retry_receiver) = bounded::<(MapReduceWorkerTask, errors::MapReduceError)>(CHANNEL_CAPACITY);
+        let (error_sender, error_receiver) = bounded::<errors::MapReduceError>(pool_size);
         let (reporter_sender, reporter_receiver) = bounded::<()>(0);
-        let (shutdown_sender, shutdown_receiver) = bounded::<()>(2);
-        let mut worker_threads: Vec<Worker> = Vec::with_capacity(*statics::MAP_REDUCE_WORKER_POOL_SIZE as usize);
-
-        for _ in 0..*statics::MAP_REDUCE_WORKER_POOL_SIZE {
-            worker_threads.push(Worker::start(
-                account_id,
-                contents.clone(),
-                path.clone(),
-                arg.clone(),
-                worker_in_receiver.clone(),
-                worker_out_sender.clone(),
-                error_sender.clone(),
-            ));
-        }
+        let (shutdown_sender, shutdown_receiver) = bounded::<Option<Duration>>(2);
+        let throttle_delay = Arc::new(Mutex::new(Duration::from_secs(0)));
+        let worker_liveness = new_worker_liveness(pool_size);
+        let contents = Arc::new(contents);
+        let path = Arc::new(path);
+
+        // Workers pick up either map or reduce closures as they free up,
+        // instead of each permanently owning one kind of work; the Lua
+        // context for a given rayon thread is built lazily the first time
+        // that thread runs a task and reused afterwards (see `run_task`).
+        let rayon_pool = ThreadPoolBuilder::new()
+            .num_threads(pool_size)
+            .build()
+            .expect("Expected to be able to build the mapreduce rayon thread pool");
 
         let reporter_thread = {
             let shutdown_receiver = shutdown_receiver.clone();
@@ -167,51 +219,174 @@ impl WorkerPool {
             })
         };
 
+        let throttle_delay_for_router = Arc::clone(&throttle_delay);
+
         let router_thread = spawn(move || -> Result<JsonValue, errors::MapReduceError> {
+            let throttle_delay = throttle_delay_for_router;
             let mut progress = 0;
-            let mut should_force_shutdown = false; 
+            let mut retries: usize = 0;
+            let mut should_force_shutdown = false;
             let mut should_gracefully_shutdown = false;
             let mut pending_tasks: usize = 0;
-            let mut report_num: usize = 0;
-            let mut last_reduced_item: Option<converters::JsonValue> = None;
+            let start_time = Instant::now();
+            let mut last_report_time = start_time;
+            let mut last_report_progress: usize = 0;
+            // The value currently sitting at each key, waiting for a partner
+            // to come along so it can be reduced. Once a key's pending
+            // reduce comes back, the result is stored right back under the
+            // same key, so by the time the job is done this holds the final
+            // per-key results.
+            let mut pending_partners: HashMap<String, converters::JsonValue> = HashMap::new();
+            // Tranquilizer bookkeeping: a rolling window of how long the
+            // most recent map tasks took (from their own dispatch instant,
+            // carried back on the output channel, not a shared FIFO rayon
+            // would complete out of order), used to keep worker business
+            // near the target ratio.
+            let mut map_durations: VecDeque<Duration> = VecDeque::with_capacity(TRANQUILIZER_WINDOW);
+            // Computed as an absolute instant as soon as a bounded shutdown is
+            // requested (rather than recomputed from a stored `Duration`
+            // later), so a deadline check against `Instant::now()` is correct
+            // the whole time the router keeps spinning on a wedged worker.
+            let mut shutdown_deadline: Option<Instant> = None;
 
             loop {
-                if !error_receiver.is_empty() {
+                if !should_force_shutdown && !error_receiver.is_empty() {
                     should_force_shutdown = true;
-                } else if shutdown_receiver.try_recv().is_ok() {
+                } else if let Ok(deadline) = shutdown_receiver.try_recv() {
                     should_gracefully_shutdown = true;
+                    shutdown_deadline = deadline.map(|timeout| Instant::now() + timeout);
                 } else if reporter_receiver.try_recv().is_ok() {
-                    println!("Mapreduce: report={}, progress={}, pending={}, winding down={}", report_num, progress, pending_tasks, should_gracefully_shutdown);
-                    report_num += 1;
-                } else if let Ok(value) = worker_out_receiver.try_recv() {
-                    pending_tasks -= 1;
-
-                    if let Some(last_reduced_item_inner) = last_reduced_item {
-                        // If this errors out, all of the workers are dead
-                        if worker_in_sender.send(WorkerTask::Reduce((last_reduced_item_inner, value))).is_err() {
-                            should_force_shutdown = true;
-                        }
-                        
-                        pending_tasks += 1;
-                        last_reduced_item = None;
+                    let now = Instant::now();
+                    let interval = now.duration_since(last_report_time).as_secs_f64();
+                    let throughput_per_sec = if interval > 0.0 {
+                        (progress - last_report_progress) as f64 / interval
                     } else {
-                        last_reduced_item = Some(value);
+                        0.0
+                    };
+
+                    if let Some(ref progress_sender) = progress_sender {
+                        progress_sender.send(ProgressReport {
+                            processed: progress,
+                            pending: pending_tasks,
+                            retries: retries,
+                            elapsed: now.duration_since(start_time),
+                            throughput_per_sec: throughput_per_sec
+                        }).ok();
                     }
-                } else if let Ok(vertex) = mapreduce_in_receiver.try_recv() {
-                    // If this errors out, all of the workers are dead
-                    if worker_in_sender.send(WorkerTask::Map(vertex)).is_err() {
+
+                    last_report_time = now;
+                    last_report_progress = progress;
+                } else if let Ok((failed_task, err)) = retry_receiver.try_recv() {
+                    pending_tasks -= 1;
+
+                    // `MAP_REDUCE_MAX_RETRIES: u32` lives in `statics` alongside
+                    // `MAP_REDUCE_WORKER_POOL_SIZE`.
+                    //
+                    // Setup failures (bad script, missing `map`/`reduce`, etc.)
+                    // will never succeed on retry no matter the attempt count,
+                    // so they're forwarded to the fatal error channel here too
+                    // -- this is also the only place that decrements
+                    // `pending_tasks` for them, so routing them through
+                    // `dispatch()`'s normal `retry_sender` path instead of
+                    // straight to `error_sender` keeps that accounting from
+                    // leaking and wedging `join()`/shutdown forever.
+                    if should_force_shutdown {
+                        // Already winding down on an earlier fatal error,
+                        // which is the one returned; this failure is neither
+                        // retried nor queued behind it.
+                    } else if matches!(err, errors::MapReduceError::WorkerSetup { .. }) || failed_task.attempt() >= *statics::MAP_REDUCE_MAX_RETRIES {
+                        // Retry budget exhausted (or non-retryable): surface
+                        // the error and wind everything down.
+                        error_sender.send(err).expect("Expected error channel to be open");
                         should_force_shutdown = true;
+                    } else {
+                        dispatch(&rayon_pool, account_id, Arc::clone(&contents), Arc::clone(&path), arg.clone(), failed_task.retry(), worker_out_sender.clone(), retry_sender.clone(), Arc::clone(&worker_liveness));
+                        retries += 1;
+                        pending_tasks += 1;
                     }
+                } else if let Ok((output, dispatched_at)) = worker_out_receiver.try_recv() {
+                    pending_tasks -= 1;
+
+                    let emitted: Vec<(String, converters::JsonValue)> = match output {
+                        MapReduceWorkerOutput::Mapped(pairs) => {
+                            if map_durations.len() >= TRANQUILIZER_WINDOW {
+                                map_durations.pop_front();
+                            }
+
+                            // `elapsed()` on this task's own dispatch instant,
+                            // not a popped-off shared queue: correct
+                            // regardless of completion order or retries.
+                            map_durations.push_back(dispatched_at.elapsed());
+
+                            // `MAP_REDUCE_TRANQUILIZER_TARGET_BUSY_RATIO: f64`
+                            // lives in `statics` alongside `MAP_REDUCE_WORKER_POOL_SIZE`.
+                            // Clamped to `(0.0, 1.0]`: a misconfigured static of
+                            // `0.0` or greater than `1.0` would otherwise turn
+                            // `(1.0 - target) / target` into a ratio
+                            // `Duration::mul_f64` panics on below.
+                            let target = statics::MAP_REDUCE_TRANQUILIZER_TARGET_BUSY_RATIO.max(std::f64::EPSILON).min(1.0);
+                            let total_nanos: u128 = map_durations.iter().map(Duration::as_nanos).sum();
+                            let avg_nanos = total_nanos / map_durations.len() as u128;
+                            let avg_duration = Duration::from_nanos(avg_nanos as u64);
+
+                            *throttle_delay.lock().expect("Expected throttle delay mutex to not be poisoned") =
+                                avg_duration.mul_f64((1.0 - target) / target);
 
+                            pairs
+                        },
+                        MapReduceWorkerOutput::Reduced((key, value)) => vec![(key, value)]
+                    };
+
+                    for (key, value) in emitted {
+                        if let Some(partner) = pending_partners.remove(&key) {
+                            dispatch(&rayon_pool, account_id, Arc::clone(&contents), Arc::clone(&path), arg.clone(), MapReduceWorkerTask::Reduce((key.clone(), partner, value), 0), worker_out_sender.clone(), retry_sender.clone(), Arc::clone(&worker_liveness));
+                            pending_tasks += 1;
+                        } else {
+                            pending_partners.insert(key, value);
+                        }
+                    }
+                } else if let Ok(vertex) = mapreduce_in_receiver.try_recv() {
+                    dispatch(&rayon_pool, account_id, Arc::clone(&contents), Arc::clone(&path), arg.clone(), MapReduceWorkerTask::Map(vertex, 0), worker_out_sender.clone(), retry_sender.clone(), Arc::clone(&worker_liveness));
                     pending_tasks += 1;
                     progress += 1;
                 }
 
-                // Check to see if we should shutdown
-                if should_force_shutdown || (should_gracefully_shutdown && pending_tasks == 0) {
-                    // Join all threads
-                    for worker_thread in worker_threads.into_iter() {
-                        worker_thread.join();
+                // Check to see if we should shutdown. A deadline (set only
+                // when `join_timeout` was used) is polled here on every pass
+                // of this already-spinning loop rather than gating the
+                // graceful path behind `pending_tasks == 0`: a wedged worker
+                // holds `pending_tasks` above zero forever, and the deadline
+                // needs to win that race regardless. In-flight tasks that do
+                // report back keep draining `pending_tasks` via the
+                // `worker_out_receiver`/`retry_receiver` branches above on
+                // each pass, during a forced shutdown as well, without a
+                // separate blocking drain loop here.
+                let shutdown_deadline_expired = shutdown_deadline
+                    .map(|deadline| Instant::now() >= deadline)
+                    .unwrap_or(false);
+
+                if (should_force_shutdown || should_gracefully_shutdown) && (pending_tasks == 0 || shutdown_deadline_expired) {
+                    // Whichever worker slots are still mid-task past the
+                    // deadline are the rayon equivalent of a stuck OS
+                    // thread: report their indices (and how long they've
+                    // been running) instead of hanging.
+                    let stuck_workers: Vec<usize> = worker_liveness.iter().enumerate()
+                        .filter_map(|(index, slot)| {
+                            let started_at = (*slot.lock().expect("Expected worker liveness mutex to not be poisoned"))?;
+                            eprintln!(
+                                "Mapreduce: worker {} has not reported back {}ms into its current task",
+                                index,
+                                started_at.elapsed().as_millis()
+                            );
+                            Some(index)
+                        })
+                        .collect();
+
+                    // `errors::MapReduceError::ShutdownTimeout { stuck_workers: Vec<usize> }`
+                    // is unchanged from the shape this variant was given
+                    // when it was first introduced.
+                    if !stuck_workers.is_empty() {
+                        return Err(errors::MapReduceError::ShutdownTimeout { stuck_workers: stuck_workers });
                     }
 
                     return if should_force_shutdown {
@@ -219,13 +394,14 @@ impl WorkerPool {
                         let first_channel_error = error_receiver.try_recv().expect("Expected to be able to read the error channel");
                         Err(first_channel_error)
                     } else {
-                        // Get the final value to return
-                        Ok(match last_reduced_item {
-                            // This should only happen if the graph is empty
-                            None => JsonValue::Null,
-                            // This should always ahppen otherwise
-                            Some(value) => value.0
-                        })
+                        // Fold the per-key results into a single JSON object.
+                        // This is `{}` if the graph was empty.
+                        let object: serde_json::Map<String, JsonValue> = pending_partners
+                            .into_iter()
+                            .map(|(key, value)| (key, value.0))
+                            .collect();
+
+                        Ok(JsonValue::Object(object))
                     }
                 }
             }
@@ -235,11 +411,17 @@ impl WorkerPool {
             reporter_thread: reporter_thread,
             router_thread: router_thread,
             in_sender: mapreduce_in_sender,
-            shutdown_sender: shutdown_sender
+            shutdown_sender: shutdown_sender,
+            throttle_delay: throttle_delay
         }
     }
 
     pub fn add_vertex(&self, vertex: Vertex) -> bool {
+        // Cooperatively slow down in proportion to how busy the workers
+        // already are, rather than just blocking once the channel fills up.
+        let delay = *self.throttle_delay.lock().expect("Expected throttle delay mutex to not be poisoned");
+        sleep(delay);
+
         self.in_sender.send(vertex).is_ok()
     }
 
@@ -249,10 +431,98 @@ impl WorkerPool {
             // This ignores the error. An error should only occur if the remote
             // end of the channel disconnected, implying that the thread crashed
             // anyways.
-            self.shutdown_sender.send(()).ok();
+            self.shutdown_sender.send(None).ok();
+        }
+
+        self.reporter_thread.join().expect("Expected reporter thread to not panic");
+        self.router_thread.join().expect("Expected router thread to not panic")
+    }
+
+    // Like `join`, but the router gives up waiting on in-flight tasks after
+    // `timeout` instead of hanging, returning `MapReduceError::ShutdownTimeout`
+    // with the indices of whichever worker slots didn't report back in time.
+    pub fn join_timeout(self, timeout: Duration) -> Result<JsonValue, errors::MapReduceError> {
+        for _ in 0..2 {
+            self.shutdown_sender.send(Some(timeout)).ok();
         }
 
         self.reporter_thread.join().expect("Expected reporter thread to not panic");
         self.router_thread.join().expect("Expected router thread to not panic")
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Barrier;
+
+    // `MapReduceWorkerPool` needs a real datastore/Lua script to exercise
+    // end-to-end, which isn't available outside of a running server. These
+    // exercise the two rayon-level guarantees `run_task`/`dispatch` actually
+    // depend on instead: that the pool gives every configured thread work at
+    // once, and that a thread_local survives across multiple tasks handed to
+    // the same worker, the way `LUA_CONTEXT` is meant to.
+
+    #[test]
+    fn rayon_pool_saturates_all_configured_threads() {
+        let thread_count = 4;
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(thread_count)
+            .build()
+            .expect("Expected to be able to build a test rayon thread pool");
+        let barrier = Arc::new(Barrier::new(thread_count));
+        let (done_sender, done_receiver) = bounded::<()>(thread_count);
+
+        for _ in 0..thread_count {
+            let barrier = Arc::clone(&barrier);
+            let done_sender = done_sender.clone();
+
+            pool.spawn(move || {
+                // Only returns once every thread has reached this point, so
+                // the test hangs (and times out below) unless all of them
+                // were scheduled concurrently.
+                barrier.wait();
+                done_sender.send(()).expect("Expected done channel to be open");
+            });
+        }
+
+        for _ in 0..thread_count {
+            done_receiver.recv_timeout(Duration::from_secs(5))
+                .expect("Expected all threads to clear the barrier concurrently");
+        }
+    }
+
+    #[test]
+    fn thread_local_state_is_reused_across_tasks_on_the_same_worker() {
+        thread_local! {
+            static CALLS: RefCell<usize> = RefCell::new(0);
+        }
+
+        let pool = ThreadPoolBuilder::new()
+            .num_threads(1)
+            .build()
+            .expect("Expected to be able to build a test rayon thread pool");
+        let (result_sender, result_receiver) = bounded::<usize>(2);
+
+        for _ in 0..2 {
+            let result_sender = result_sender.clone();
+
+            pool.spawn(move || {
+                let calls = CALLS.with(|cell| {
+                    *cell.borrow_mut() += 1;
+                    *cell.borrow()
+                });
+
+                result_sender.send(calls).expect("Expected result channel to be open");
+            });
+        }
+
+        let first = result_receiver.recv_timeout(Duration::from_secs(5)).expect("Expected first task to complete");
+        let second = result_receiver.recv_timeout(Duration::from_secs(5)).expect("Expected second task to complete");
+
+        // A single-thread pool runs both tasks on the same OS thread, so its
+        // thread_local carries over between them exactly as LUA_CONTEXT does
+        // for run_task.
+        assert_eq!((first, second), (1, 2));
+    }
 }
\ No newline at end of file
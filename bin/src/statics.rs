@@ -0,0 +1,30 @@
+//! Process-wide configuration, read once from the environment and cached
+//! behind `lazy_static!`. Every entry here is accessed elsewhere in the
+//! crate via `*statics::SOME_NAME`.
+
+use std::env::var;
+
+fn get_env_var_or_default<T: std::str::FromStr>(name: &str, default: T) -> T {
+    var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+lazy_static! {
+    /// Number of rayon worker threads a mapreduce job's pool is built with.
+    pub static ref MAP_REDUCE_WORKER_POOL_SIZE: usize =
+        get_env_var_or_default("MAP_REDUCE_WORKER_POOL_SIZE", 4);
+
+    /// How many times a single map/reduce task is retried after a
+    /// non-setup failure before the job is aborted.
+    pub static ref MAP_REDUCE_MAX_RETRIES: u32 =
+        get_env_var_or_default("MAP_REDUCE_MAX_RETRIES", 3);
+
+    /// Target ratio of task busy time to busy-plus-throttle time for the
+    /// tranquilizer: before each vertex, `add_vertex` sleeps for the
+    /// average recent map task duration times `(1 - ratio) / ratio`. No
+    /// busy ratio is measured, and the pool size is not taken into account.
+    pub static ref MAP_REDUCE_TRANQUILIZER_TARGET_BUSY_RATIO: f64 =
+        get_env_var_or_default("MAP_REDUCE_TRANQUILIZER_TARGET_BUSY_RATIO", 0.75);
+}